@@ -1,22 +1,62 @@
+use futures::executor::{self, Notify, Spawn};
+use futures::task;
 use futures::Async::*;
 use futures::{Poll, Stream};
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::error::Error as StdError;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
+/// A source's own error, erased so `LogMerge` doesn't need to know what
+/// kind of thing it's tailing (file, socket, ...).
+pub type SourceError = Box<StdError + Send>;
+
 #[derive(Debug)]
 pub enum LogMergeError {
-    DefaultError,
+    /// `source_idx` failed with `cause`. Under `ErrorPolicy::Isolate` these
+    /// accumulate in `LogMerge::errors` instead of ending the stream.
+    Source {
+        source_idx: usize,
+        cause: SourceError,
+    },
 }
 
 impl Display for LogMergeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Failed stream.")
+        match self {
+            LogMergeError::Source { source_idx, cause } => {
+                write!(f, "source {} failed: {}", source_idx, cause)
+            }
+        }
     }
 }
 
-pub type LogStream = Box<Stream<Item = String, Error = LogMergeError>>;
+impl StdError for LogMergeError {}
+
+/// How `LogMerge` reacts when a source's stream returns an error.
+#[derive(PartialEq, Clone, Copy)]
+pub enum ErrorPolicy {
+    /// Fail the whole merge, as before.
+    FailFast,
+    /// Mark the failing source `Finished`, record the error, and keep
+    /// merging the remaining sources to completion.
+    Isolate,
+}
+
+pub type LogStream = Box<Stream<Item = String, Error = SourceError>>;
+
+/// A timestamp as parsed out of a log line. Sources don't agree on a wall
+/// clock format, so this is just whatever ordinal value the pluggable
+/// extractor produces (e.g. millis since epoch).
+pub type Timestamp = i64;
+
+/// Parses a `Timestamp` out of a raw line, or `None` if the line doesn't
+/// carry one (e.g. a wrapped continuation line).
+pub type TimestampExtractor = Box<Fn(&str) -> Option<Timestamp>>;
 
 #[derive(PartialEq)]
 enum SourceState {
@@ -25,39 +65,313 @@ enum SourceState {
     Finished,
 }
 
+/// Result of a single `LogMerge::poll_source` attempt.
+enum PollOutcome {
+    /// The source delivered, finished, or errored out.
+    Progressed,
+    /// The line was filtered out; the source is left at `NeedsPoll` for a
+    /// later pass, but this alone isn't progress.
+    Filtered,
+    NotReady,
+}
+
+/// Tracks which source indices actually signaled readiness, in the spirit
+/// of `FuturesUnordered`, so a poll only re-drives sources that woke their
+/// task instead of rescanning every source every time.
+struct ReadyQueue {
+    queue: Mutex<VecDeque<usize>>,
+    queued: Vec<AtomicBool>,
+}
+
+impl ReadyQueue {
+    fn new(len: usize) -> ReadyQueue {
+        ReadyQueue {
+            queue: Mutex::new((0..len).collect()),
+            queued: (0..len).map(|_| AtomicBool::new(true)).collect(),
+        }
+    }
+
+    fn pop(&self) -> Option<usize> {
+        let idx = self.queue.lock().unwrap().pop_front();
+        if let Some(idx) = idx {
+            self.queued[idx].store(false, Ordering::SeqCst);
+        }
+        idx
+    }
+
+    fn requeue(&self, idx: usize) {
+        if !self.queued[idx].swap(true, Ordering::SeqCst) {
+            self.queue.lock().unwrap().push_back(idx);
+        }
+    }
+}
+
+impl Notify for ReadyQueue {
+    fn notify(&self, id: usize) {
+        self.requeue(id);
+    }
+}
+
+/// A line, lightly parsed into the fields most aggregators want to filter
+/// on. Unrecognized lines simply end up with everything but `raw` empty.
+///
+/// Recognized lines start with whitespace-separated `key=value` tokens:
+/// `sev=<i32>`, `tag=<comma,separated,list>`, `pid=<u64>`, `tid=<u64>`.
+/// Parsing stops at the first token that isn't one of those.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub severity: Option<i32>,
+    pub tags: Vec<String>,
+    pub pid: Option<u64>,
+    pub tid: Option<u64>,
+    pub raw: String,
+}
+
+impl LogRecord {
+    fn parse(raw: &str) -> LogRecord {
+        let mut severity = None;
+        let mut tags = Vec::new();
+        let mut pid = None;
+        let mut tid = None;
+        for token in raw.split_whitespace() {
+            if let Some(rest) = token.strip_prefix("sev=") {
+                match rest.parse() {
+                    Ok(s) => severity = Some(s),
+                    Err(_) => break,
+                }
+            } else if let Some(rest) = token.strip_prefix("tag=") {
+                tags.extend(rest.split(',').map(String::from));
+            } else if let Some(rest) = token.strip_prefix("pid=") {
+                match rest.parse() {
+                    Ok(p) => pid = Some(p),
+                    Err(_) => break,
+                }
+            } else if let Some(rest) = token.strip_prefix("tid=") {
+                match rest.parse() {
+                    Ok(t) => tid = Some(t),
+                    Err(_) => break,
+                }
+            } else {
+                break;
+            }
+        }
+        LogRecord {
+            severity,
+            tags,
+            pid,
+            tid,
+            raw: raw.to_string(),
+        }
+    }
+}
+
+/// Drops records before they're buffered for emission. A record passes if
+/// it clears `min_severity` (when set), matches `pid`/`tid` (when set), and
+/// has at least one tag in common with `tags` (when non-empty).
+#[derive(Default)]
+pub struct LogFilter {
+    pub min_severity: Option<i32>,
+    pub tags: HashSet<String>,
+    pub pid: Option<u64>,
+    pub tid: Option<u64>,
+}
+
+impl LogFilter {
+    fn matches(&self, record: &LogRecord) -> bool {
+        if let Some(min_severity) = self.min_severity {
+            match record.severity {
+                Some(severity) if severity >= min_severity => {}
+                _ => return false,
+            }
+        }
+        if let Some(pid) = self.pid {
+            if record.pid != Some(pid) {
+                return false;
+            }
+        }
+        if let Some(tid) = self.tid {
+            if record.tid != Some(tid) {
+                return false;
+            }
+        }
+        if !self.tags.is_empty() && !record.tags.iter().any(|tag| self.tags.contains(tag)) {
+            return false;
+        }
+        true
+    }
+}
+
 struct LogLine {
     source_idx: usize,
     line: String,
+    timestamp: Timestamp,
+}
+
+/// What to do when buffered-but-unconsumed lines would exceed the byte
+/// budget.
+#[derive(PartialEq, Clone, Copy)]
+pub enum BufferPolicy {
+    /// Stop polling `NeedsPoll` sources once the budget is reached, so the
+    /// merge applies backpressure to whatever's feeding the sources.
+    Backpressure,
+    /// Evict the oldest buffered line to make room, tracked via
+    /// `LogMerge::dropped_count`. Oldest is by arrival (FIFO) under
+    /// round-robin order and by smallest `Timestamp` under chronological
+    /// order.
+    DropOldest,
+}
+
+/// Caps how much memory `LogMerge` lets its internal buffer grow to.
+#[derive(Clone, Copy)]
+pub struct BufferConfig {
+    pub budget_bytes: usize,
+    pub policy: BufferPolicy,
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig {
+            budget_bytes: 4 * 1024 * 1024,
+            policy: BufferPolicy::Backpressure,
+        }
+    }
+}
+
+/// How `LogMerge` picks the next line to emit among buffered sources.
+enum Order {
+    /// Round-robin: emit buffered lines in source index order, as before.
+    RoundRobin,
+    /// Chronological k-way merge keyed by `Timestamp`.
+    Chronological {
+        extractor: TimestampExtractor,
+        last_timestamp: Vec<Option<Timestamp>>,
+        heap: BinaryHeap<Reverse<(Timestamp, usize)>>,
+    },
 }
 
 pub struct LogMerge {
-    sources: Vec<LogStream>,
+    spawned: Vec<Spawn<LogStream>>,
+    ready: Arc<ReadyQueue>,
     source_state: Vec<SourceState>,
     finished: usize,
-    buffer: VecDeque<LogLine>,
+    // At most one buffered line per source (enforced by `SourceState`), so
+    // the payload lives in a by-source slot for O(1) lookup/removal.
+    // `buffer_order` only tracks insertion order for `Order::RoundRobin`
+    // (both its own emission and its DropOldest eviction); `Order::Chronological`
+    // never pushes to it and instead reuses its own timestamp heap for
+    // eviction too, so neither structure grows unbounded under either
+    // order. Both the heap and `buffer_order` can hold entries for slots
+    // already taken; consumers skip past `None` slots (lazy deletion).
+    buffer_order: VecDeque<usize>,
+    buffer_by_source: Vec<Option<LogLine>>,
+    buffer_count: usize,
+    buffer_bytes: usize,
+    buffer_config: BufferConfig,
+    dropped_count: usize,
+    order: Order,
+    filter: Option<LogFilter>,
+    error_policy: ErrorPolicy,
+    errors: Vec<(usize, LogMergeError)>,
 }
 
 impl LogMerge {
-    pub fn new(sources: Vec<LogStream>) -> LogMerge {
+    pub fn new(sources: Vec<LogStream>, filter: Option<LogFilter>) -> LogMerge {
+        LogMerge::with_order(sources, Order::RoundRobin, filter, None)
+    }
+
+    /// Like `new`, but merges sources chronologically instead of
+    /// round-robin. `extractor` parses a `Timestamp` out of each raw line;
+    /// lines it can't parse (continuation lines, stack traces, ...) inherit
+    /// the last timestamp seen on that same source so they stay grouped
+    /// with the line they belong to.
+    pub fn new_ordered<F>(
+        sources: Vec<LogStream>,
+        extractor: F,
+        filter: Option<LogFilter>,
+    ) -> LogMerge
+    where
+        F: Fn(&str) -> Option<Timestamp> + 'static,
+    {
         let num_sources = sources.len();
-        let mut source_state = Vec::with_capacity(sources.len());
-        for _ in 0..sources.len() {
+        LogMerge::with_order(
+            sources,
+            Order::Chronological {
+                extractor: Box::new(extractor),
+                last_timestamp: vec![None; num_sources],
+                heap: BinaryHeap::new(),
+            },
+            filter,
+            None,
+        )
+    }
+
+    /// Sets the buffer's byte budget and eviction policy; defaults to a 4
+    /// MB budget under backpressure when not called.
+    pub fn with_buffer_config(mut self, buffer_config: BufferConfig) -> LogMerge {
+        self.buffer_config = buffer_config;
+        self
+    }
+
+    /// Count of lines evicted under `BufferPolicy::DropOldest`.
+    pub fn dropped_count(&self) -> usize {
+        self.dropped_count
+    }
+
+    /// Sets how the merge reacts to a source's stream returning an error;
+    /// defaults to `ErrorPolicy::FailFast` when not called.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> LogMerge {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Errors recorded from isolated sources under `ErrorPolicy::Isolate`,
+    /// in the order they occurred.
+    pub fn errors(&self) -> &[(usize, LogMergeError)] {
+        &self.errors
+    }
+
+    fn with_order(
+        sources: Vec<LogStream>,
+        order: Order,
+        filter: Option<LogFilter>,
+        buffer_config: Option<BufferConfig>,
+    ) -> LogMerge {
+        let num_sources = sources.len();
+        let mut source_state = Vec::with_capacity(num_sources);
+        for _ in 0..num_sources {
             source_state.push(SourceState::NeedsPoll);
         }
         LogMerge {
-            sources: sources,
+            spawned: sources.into_iter().map(executor::spawn).collect(),
+            ready: Arc::new(ReadyQueue::new(num_sources)),
             source_state: source_state,
             finished: 0,
-            buffer: VecDeque::with_capacity(num_sources),
+            buffer_order: VecDeque::with_capacity(num_sources),
+            buffer_by_source: (0..num_sources).map(|_| None).collect(),
+            buffer_count: 0,
+            buffer_bytes: 0,
+            buffer_config: buffer_config.unwrap_or_default(),
+            dropped_count: 0,
+            order: order,
+            filter: filter,
+            error_policy: ErrorPolicy::FailFast,
+            errors: Vec::new(),
         }
     }
 
     fn state(&self) -> SourceState {
-        // println!("finished: {} of {}", self.finished, self.sources.len());
-        let unfinished = self.sources.len() - self.finished;
+        // println!("finished: {} of {}", self.finished, self.spawned.len());
+        let unfinished = self.spawned.len() - self.finished;
+        let at_budget_backpressure = self.buffer_config.policy == BufferPolicy::Backpressure
+            && self.buffer_bytes >= self.buffer_config.budget_bytes;
         if unfinished == 0 {
             SourceState::Finished
-        } else if unfinished == self.buffer.len() {
+        } else if unfinished == self.buffer_count {
+            SourceState::Delivered
+        } else if at_budget_backpressure && self.buffer_count > 0 {
+            // We're deliberately holding off on polling the remaining
+            // sources to respect the byte budget, so drain what's already
+            // buffered rather than waiting on them forever.
             SourceState::Delivered
         } else {
             SourceState::NeedsPoll
@@ -65,32 +379,155 @@ impl LogMerge {
     }
 
     fn next_line(&mut self) -> LogLine {
-        self.buffer.pop_front().unwrap()
+        let log_line = match self.order {
+            Order::RoundRobin => loop {
+                let source_idx = self.buffer_order.pop_front().unwrap();
+                if let Some(log_line) = self.buffer_by_source[source_idx].take() {
+                    break log_line;
+                }
+            },
+            Order::Chronological { ref mut heap, .. } => loop {
+                // The heap can hold entries for lines that were since
+                // evicted (DropOldest) or already emitted; skip those.
+                let Reverse((ts, source_idx)) = heap.pop().unwrap();
+                match self.buffer_by_source[source_idx] {
+                    Some(ref log_line) if log_line.timestamp == ts => {}
+                    _ => continue,
+                }
+                break self.buffer_by_source[source_idx].take().unwrap();
+            },
+        };
+        self.buffer_count -= 1;
+        self.buffer_bytes -= log_line.line.len();
+        log_line
     }
 
-    fn insert_into_buffer(&mut self, line: LogLine) {
-        self.buffer.push_back(line);
+    /// Updates `Order::Chronological`'s per-source last-seen timestamp from
+    /// `line`, independent of whether `line` ends up buffered. A no-op
+    /// under `Order::RoundRobin`.
+    fn record_timestamp(&mut self, source_idx: usize, line: &str) {
+        if let Order::Chronological {
+            ref extractor,
+            ref mut last_timestamp,
+            ..
+        } = self.order
+        {
+            if let Some(ts) = extractor(line) {
+                last_timestamp[source_idx] = Some(ts);
+            }
+        }
+    }
+
+    fn insert_into_buffer(&mut self, source_idx: usize, line: String) {
+        let timestamp = match self.order {
+            Order::RoundRobin => 0,
+            Order::Chronological {
+                ref extractor,
+                ref mut last_timestamp,
+                ..
+            } => {
+                let parsed = extractor(&line);
+                if let Some(ts) = parsed {
+                    last_timestamp[source_idx] = Some(ts);
+                }
+                parsed.or(last_timestamp[source_idx]).unwrap_or(0)
+            }
+        };
+        let line_len = line.len();
+        if self.buffer_config.policy == BufferPolicy::DropOldest {
+            while self.buffer_count > 0
+                && self.buffer_bytes + line_len > self.buffer_config.budget_bytes
+            {
+                let evicted = match self.order {
+                    Order::RoundRobin => loop {
+                        let evicted_idx = self.buffer_order.pop_front().unwrap();
+                        if let Some(evicted) = self.buffer_by_source[evicted_idx].take() {
+                            break evicted;
+                        }
+                        // Else already emitted; stale queue entry, keep looking.
+                    },
+                    Order::Chronological { ref mut heap, .. } => loop {
+                        // Evict chronologically-oldest first, same
+                        // lazy-deletion skip as emission uses.
+                        let Reverse((ts, evicted_idx)) = heap.pop().unwrap();
+                        match self.buffer_by_source[evicted_idx] {
+                            Some(ref log_line) if log_line.timestamp == ts => {}
+                            _ => continue,
+                        }
+                        break self.buffer_by_source[evicted_idx].take().unwrap();
+                    },
+                };
+                self.buffer_count -= 1;
+                self.buffer_bytes -= evicted.line.len();
+                self.dropped_count += 1;
+                self.source_state[evicted.source_idx] = SourceState::NeedsPoll;
+                // Nothing will wake us for this source on its own now that
+                // its peeked line is gone, so schedule a re-poll ourselves.
+                self.ready.requeue(evicted.source_idx);
+            }
+        }
+        self.buffer_bytes += line_len;
+        // Only enqueued after eviction, so the eviction loop above can't
+        // pop the incoming line's own entry and skip it as stale.
+        match self.order {
+            Order::RoundRobin => self.buffer_order.push_back(source_idx),
+            Order::Chronological { ref mut heap, .. } => {
+                heap.push(Reverse((timestamp, source_idx)))
+            }
+        }
+        self.buffer_by_source[source_idx] = Some(LogLine {
+            source_idx,
+            line,
+            timestamp,
+        });
+        self.buffer_count += 1;
     }
 
-    fn poll_source(&mut self, source_idx: usize) -> Result<(), LogMergeError> {
-        match self.sources[source_idx].poll() {
+    /// Polls a single source exactly once. `Progressed` means the caller's
+    /// fixed-point loop should keep spinning; `Filtered` means the line was
+    /// dropped and the source was left at `NeedsPoll` for a later pass,
+    /// without itself counting as progress, so a source that filters out
+    /// every line it produces can't spin that loop forever and starve
+    /// everything else on the executor.
+    fn poll_source(&mut self, source_idx: usize) -> Result<PollOutcome, LogMergeError> {
+        let ready = self.ready.clone();
+        match self.spawned[source_idx].poll_stream_notify(&ready, source_idx) {
             Ok(Ready(Some(line))) => {
-                let log_line = LogLine { source_idx, line };
-                self.insert_into_buffer(log_line);
+                if let Some(ref filter) = self.filter {
+                    if !filter.matches(&LogRecord::parse(&line)) {
+                        // Record the timestamp even though the line itself
+                        // is dropped, so a later continuation line on this
+                        // source still inherits it instead of a stale one.
+                        self.record_timestamp(source_idx, &line);
+                        return Ok(PollOutcome::Filtered);
+                    }
+                }
+                self.insert_into_buffer(source_idx, line);
                 self.source_state[source_idx] = SourceState::Delivered;
+                Ok(PollOutcome::Progressed)
             }
             Ok(Ready(None)) => {
                 self.source_state[source_idx] = SourceState::Finished;
                 self.finished += 1;
+                Ok(PollOutcome::Progressed)
             }
             Ok(NotReady) => {
                 self.source_state[source_idx] = SourceState::NeedsPoll;
+                Ok(PollOutcome::NotReady)
             }
-            Err(_) => {
-                return Err(LogMergeError::DefaultError);
+            Err(cause) => {
+                let err = LogMergeError::Source { source_idx, cause };
+                match self.error_policy {
+                    ErrorPolicy::FailFast => Err(err),
+                    ErrorPolicy::Isolate => {
+                        self.source_state[source_idx] = SourceState::Finished;
+                        self.finished += 1;
+                        self.errors.push((source_idx, err));
+                        Ok(PollOutcome::Progressed)
+                    }
+                }
             }
         }
-        Ok(())
     }
 }
 
@@ -100,24 +537,75 @@ impl Stream for LogMerge {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         // println!("poll");
-        for s in 0..self.source_state.len() {
-            match self.source_state[s] {
-                SourceState::NeedsPoll => {
-                    if let Err(err) = self.poll_source(s) {
-                        return Err(err);
+        loop {
+            let mut progressed = false;
+            let mut filtered = false;
+            let dropped_before = self.dropped_count;
+            // Only sources that actually signaled readiness get polled here
+            // (via `ReadyQueue`/`Notify`), rather than rescanning every
+            // source on every call.
+            let mut deferred = Vec::new();
+            while let Some(idx) = self.ready.pop() {
+                if self.source_state[idx] != SourceState::NeedsPoll {
+                    // Stale readiness notification for an already-delivered
+                    // or already-finished source; ignore it.
+                    continue;
+                }
+                // Recomputed per source: a burst of simultaneously-ready
+                // sources must stop being drained the moment the budget is
+                // crossed, not just once per outer pass.
+                let at_budget = self.buffer_bytes >= self.buffer_config.budget_bytes;
+                if at_budget && self.buffer_config.policy == BufferPolicy::Backpressure {
+                    // Leave this source pending; applying backpressure
+                    // beats growing the buffer further. Re-enqueue it for
+                    // the next poll once the budget may have freed up.
+                    deferred.push(idx);
+                    continue;
+                }
+                match self.poll_source(idx) {
+                    Ok(PollOutcome::Progressed) => progressed = true,
+                    Ok(PollOutcome::Filtered) => {
+                        // Defer the re-queue past this drain pass so a
+                        // source that filters out everything it produces
+                        // gets one attempt per pass rather than spinning
+                        // the ready queue on itself forever.
+                        deferred.push(idx);
+                        filtered = true;
                     }
+                    Ok(PollOutcome::NotReady) => {}
+                    Err(err) => return Err(err),
                 }
-                _ => {}
             }
-        }
-        match self.state() {
-            SourceState::Delivered => {
-                let log_line = self.next_line();
-                self.source_state[log_line.source_idx] = SourceState::NeedsPoll;
-                Ok(Ready(Some(log_line.line)))
+            for idx in deferred {
+                self.ready.requeue(idx);
+            }
+            if self.dropped_count != dropped_before {
+                progressed = true;
+            }
+            if filtered && !progressed {
+                // Nothing else moved this pass, so we're about to return
+                // NotReady below with a source still sitting on the ready
+                // queue; wake ourselves so the executor gives us another
+                // turn instead of waiting on a notification that will never
+                // come.
+                task::current().notify();
+            }
+            match self.state() {
+                SourceState::Delivered => {
+                    let log_line = self.next_line();
+                    self.source_state[log_line.source_idx] = SourceState::NeedsPoll;
+                    // It just delivered, so it's worth trying again for
+                    // more rather than waiting on its own notification.
+                    self.ready.requeue(log_line.source_idx);
+                    return Ok(Ready(Some(log_line.line)));
+                }
+                SourceState::Finished => return Ok(Ready(None)),
+                SourceState::NeedsPoll => {
+                    if !progressed {
+                        return Ok(NotReady);
+                    }
+                }
             }
-            SourceState::Finished => Ok(Ready(None)),
-            SourceState::NeedsPoll => Ok(NotReady),
         }
     }
 }
@@ -126,7 +614,7 @@ impl Stream for LogMerge {
 mod tests {
     use crate::log_merge::{LogMerge, LogStream};
     use futures::stream::{empty, iter_ok, once};
-    use futures::Stream;
+    use futures::{Async, Stream};
     use tokio::runtime::current_thread::Runtime;
 
     #[test]
@@ -134,8 +622,8 @@ mod tests {
         let s1: LogStream = Box::new(once(Ok(String::from("s1"))));
         let s2: LogStream = Box::new(once(Ok(String::from("s2"))));
         let sources = vec![s1, s2];
-        let merge = LogMerge::new(sources);
-        assert!(merge.sources.len() == 2);
+        let merge = LogMerge::new(sources, None);
+        assert!(merge.spawned.len() == 2);
         assert!(merge.source_state.len() == 2);
     }
 
@@ -143,7 +631,7 @@ mod tests {
     fn empty_streams() {
         let s1: LogStream = Box::new(empty());
         let sources = vec![s1];
-        let merge = LogMerge::new(sources);
+        let merge = LogMerge::new(sources, None);
         let mut rt = Runtime::new().unwrap();
         let result = rt.block_on(merge.collect()).unwrap();
         assert!(result.is_empty());
@@ -153,7 +641,7 @@ mod tests {
     fn test_single_stream() {
         let s1: LogStream = Box::new(iter_ok(vec![String::from("s11"), String::from("s12")]));
         let sources = vec![s1];
-        let merge = LogMerge::new(sources);
+        let merge = LogMerge::new(sources, None);
         let mut rt = Runtime::new().unwrap();
         let result = rt.block_on(merge.collect()).unwrap();
         assert_eq!(vec![String::from("s11"), String::from("s12")], result);
@@ -165,7 +653,7 @@ mod tests {
         let s2: LogStream = Box::new(iter_ok(vec![String::from("s21"), String::from("s22")]));
         let s3: LogStream = Box::new(iter_ok(vec![String::from("s31"), String::from("s32")]));
         let sources = vec![s1, s2, s3];
-        let merge = LogMerge::new(sources);
+        let merge = LogMerge::new(sources, None);
         let mut rt = Runtime::new().unwrap();
         let result = rt.block_on(merge.collect()).unwrap();
         assert_eq!(
@@ -191,7 +679,7 @@ mod tests {
             String::from("s33"),
         ]));
         let sources = vec![s1, s2, s3];
-        let merge = LogMerge::new(sources);
+        let merge = LogMerge::new(sources, None);
         let mut rt = Runtime::new().unwrap();
         let result = rt.block_on(merge.collect()).unwrap();
         assert_eq!(
@@ -206,4 +694,312 @@ mod tests {
             result
         );
     }
+
+    #[test]
+    fn test_ordered_merge_by_timestamp() {
+        fn ts(line: &str) -> Option<i64> {
+            line.split(' ').next().and_then(|s| s.parse().ok())
+        }
+
+        let s1: LogStream = Box::new(iter_ok(vec![
+            String::from("1 s1-a"),
+            String::from("4 s1-b"),
+        ]));
+        let s2: LogStream = Box::new(iter_ok(vec![
+            String::from("2 s2-a"),
+            String::from("3 s2-b"),
+        ]));
+        let sources = vec![s1, s2];
+        let merge = LogMerge::new_ordered(sources, ts, None);
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(
+            vec![
+                String::from("1 s1-a"),
+                String::from("2 s2-a"),
+                String::from("3 s2-b"),
+                String::from("4 s1-b"),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_ordered_merge_continuation_lines_inherit_timestamp() {
+        fn ts(line: &str) -> Option<i64> {
+            line.split(' ').next().and_then(|s| s.parse().ok())
+        }
+
+        let s1: LogStream = Box::new(iter_ok(vec![
+            String::from("1 s1-a"),
+            String::from("  at s1-a.trace"),
+        ]));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("2 s2-a")]));
+        let sources = vec![s1, s2];
+        let merge = LogMerge::new_ordered(sources, ts, None);
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(
+            vec![
+                String::from("1 s1-a"),
+                String::from("  at s1-a.trace"),
+                String::from("2 s2-a"),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_ordered_merge_under_backpressure_does_not_leak_buffer_order() {
+        fn ts(line: &str) -> Option<i64> {
+            line.split(' ').next().and_then(|s| s.parse().ok())
+        }
+
+        let num_lines = 5000;
+        let lines: Vec<String> = (0..num_lines).map(|i| format!("{} line{}", i, i)).collect();
+        let s1: LogStream = Box::new(iter_ok(lines));
+        let sources = vec![s1];
+        let mut merge = LogMerge::new_ordered(sources, ts, None);
+        let mut emitted = 0;
+        loop {
+            match merge.poll().unwrap() {
+                Async::Ready(Some(_)) => {
+                    emitted += 1;
+                    // Chronological mode never uses `buffer_order`; it must
+                    // stay empty under the default Backpressure policy
+                    // instead of accumulating a dead entry per emitted line.
+                    assert_eq!(merge.buffer_order.len(), 0);
+                }
+                Async::Ready(None) => break,
+                Async::NotReady => panic!("s1 is always ready"),
+            }
+        }
+        assert_eq!(emitted, num_lines);
+    }
+
+    #[test]
+    fn test_ordered_merge_filtered_line_still_contributes_timestamp() {
+        use crate::log_merge::LogFilter;
+
+        // `sev=` must lead the line for `LogRecord::parse` to pick it up
+        // (it stops at the first token it doesn't recognize), so the
+        // timestamp rides in its own `ts=` token instead.
+        fn ts(line: &str) -> Option<i64> {
+            line.split_whitespace()
+                .find_map(|tok| tok.strip_prefix("ts="))
+                .and_then(|s| s.parse().ok())
+        }
+
+        // s1's first line is filtered out, but its timestamp (10) must
+        // still be recorded so the continuation line that follows it sorts
+        // after s2's ts=5 line instead of inheriting a stale/default 0.
+        let s1: LogStream = Box::new(iter_ok(vec![
+            String::from("sev=1 ts=10 original message"),
+            String::from("sev=5 continuation of that message"),
+        ]));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("sev=5 ts=5 unrelated message")]));
+        let sources = vec![s1, s2];
+        let filter = LogFilter {
+            min_severity: Some(3),
+            ..Default::default()
+        };
+        let merge = LogMerge::new_ordered(sources, ts, Some(filter));
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(
+            vec![
+                String::from("sev=5 ts=5 unrelated message"),
+                String::from("sev=5 continuation of that message"),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_filter_drops_below_min_severity() {
+        use crate::log_merge::LogFilter;
+
+        let s1: LogStream = Box::new(iter_ok(vec![
+            String::from("sev=1 tag=http low severity, should be dropped"),
+            String::from("sev=5 tag=http kept"),
+        ]));
+        let sources = vec![s1];
+        let filter = LogFilter {
+            min_severity: Some(3),
+            ..Default::default()
+        };
+        let merge = LogMerge::new(sources, Some(filter));
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(vec![String::from("sev=5 tag=http kept")], result);
+    }
+
+    #[test]
+    fn test_filter_requires_tag_overlap() {
+        use crate::log_merge::LogFilter;
+        use std::collections::HashSet;
+
+        let s1: LogStream = Box::new(iter_ok(vec![
+            String::from("tag=db,cache not matching"),
+            String::from("tag=http,db matching"),
+        ]));
+        let sources = vec![s1];
+        let mut tags = HashSet::new();
+        tags.insert(String::from("http"));
+        let filter = LogFilter {
+            tags,
+            ..Default::default()
+        };
+        let merge = LogMerge::new(sources, Some(filter));
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(vec![String::from("tag=http,db matching")], result);
+    }
+
+    #[test]
+    fn test_filter_drops_entire_noisy_source_without_stalling() {
+        use crate::log_merge::LogFilter;
+
+        // s1 filters out every line it ever produces; the merge must still
+        // make progress on s2 and finish rather than hanging forever.
+        let s1: LogStream = Box::new(iter_ok(
+            (0..1000)
+                .map(|_| String::from("sev=1 tag=heartbeat noise"))
+                .collect::<Vec<_>>(),
+        ));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("sev=5 tag=http kept")]));
+        let sources = vec![s1, s2];
+        let filter = LogFilter {
+            min_severity: Some(3),
+            ..Default::default()
+        };
+        let merge = LogMerge::new(sources, Some(filter));
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(vec![String::from("sev=5 tag=http kept")], result);
+    }
+
+    #[test]
+    fn test_drop_oldest_evicts_over_budget_and_counts_drops() {
+        use crate::log_merge::{BufferConfig, BufferPolicy};
+
+        let s1: LogStream = Box::new(iter_ok(vec![String::from("s11")]));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("s21")]));
+        let sources = vec![s1, s2];
+        let merge = LogMerge::new(sources, None).with_buffer_config(BufferConfig {
+            budget_bytes: 3,
+            policy: BufferPolicy::DropOldest,
+        });
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        // "s11" (3 bytes) is evicted to make room for "s21" (3 bytes).
+        assert_eq!(vec![String::from("s21")], result);
+    }
+
+    #[test]
+    fn test_backpressure_preserves_all_lines() {
+        use crate::log_merge::BufferConfig;
+
+        let s1: LogStream = Box::new(iter_ok(vec![String::from("s11"), String::from("s12")]));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("s21")]));
+        let sources = vec![s1, s2];
+        let merge = LogMerge::new(sources, None).with_buffer_config(BufferConfig {
+            budget_bytes: 3,
+            ..Default::default()
+        });
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(
+            vec![String::from("s11"), String::from("s21"), String::from("s12")],
+            result
+        );
+    }
+
+    #[test]
+    fn test_backpressure_stops_mid_burst_not_just_between_polls() {
+        use crate::log_merge::BufferConfig;
+
+        let num_sources = 50;
+        let sources: Vec<LogStream> = (0..num_sources)
+            .map(|i| -> LogStream { Box::new(iter_ok(vec![format!("s{:03}", i)])) })
+            .collect();
+        let mut merge = LogMerge::new(sources, None).with_buffer_config(BufferConfig {
+            budget_bytes: 5,
+            ..Default::default()
+        });
+        // All sources are immediately ready, so a single poll() call drains
+        // the whole readiness queue; the budget must still stop us once it
+        // is reached (after the second 4-byte line) rather than letting the
+        // entire burst through.
+        merge.poll().unwrap();
+        assert!(merge.buffer_bytes <= 5);
+    }
+
+    #[derive(Debug)]
+    struct FakeSourceError;
+
+    impl std::fmt::Display for FakeSourceError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "fake source error")
+        }
+    }
+
+    impl std::error::Error for FakeSourceError {}
+
+    #[test]
+    fn test_fail_fast_ends_whole_merge() {
+        use crate::log_merge::SourceError;
+        use futures::stream::iter_result;
+
+        let s1: LogStream = Box::new(iter_result(vec![
+            Ok(String::from("s11")),
+            Err(Box::new(FakeSourceError) as SourceError),
+        ]));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("s21")]));
+        let sources = vec![s1, s2];
+        let merge = LogMerge::new(sources, None);
+        let mut rt = Runtime::new().unwrap();
+        assert!(rt.block_on(merge.collect()).is_err());
+    }
+
+    #[test]
+    fn test_isolate_keeps_healthy_sources_alive() {
+        use crate::log_merge::{ErrorPolicy, SourceError};
+        use futures::stream::iter_result;
+
+        let s1: LogStream = Box::new(iter_result(vec![
+            Ok(String::from("s11")),
+            Err(Box::new(FakeSourceError) as SourceError),
+        ]));
+        let s2: LogStream = Box::new(iter_ok(vec![String::from("s21"), String::from("s22")]));
+        let sources = vec![s1, s2];
+        let merge = LogMerge::new(sources, None).with_error_policy(ErrorPolicy::Isolate);
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(
+            vec![String::from("s11"), String::from("s21"), String::from("s22")],
+            result
+        );
+    }
+
+    #[test]
+    fn test_many_sources_all_lines_delivered() {
+        let num_sources = 500;
+        let sources: Vec<LogStream> = (0..num_sources)
+            .map(|i| -> LogStream {
+                Box::new(iter_ok(vec![
+                    format!("s{}a", i),
+                    format!("s{}b", i),
+                ]))
+            })
+            .collect();
+        let merge = LogMerge::new(sources, None);
+        let mut rt = Runtime::new().unwrap();
+        let result = rt.block_on(merge.collect()).unwrap();
+        assert_eq!(result.len(), num_sources * 2);
+        for i in 0..num_sources {
+            assert!(result.contains(&format!("s{}a", i)));
+            assert!(result.contains(&format!("s{}b", i)));
+        }
+    }
 }